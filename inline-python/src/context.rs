@@ -1,6 +1,9 @@
-use crate::run::run_python_code;
+use crate::run::{eval_python_code, run_python_code};
 use crate::PythonBlock;
-use pyo3::{ffi, types::PyDict, AsPyPointer, FromPyObject, IntoPy, PyErr, PyObject, PyResult, Python, ToPyObject};
+use pyo3::{
+	exceptions::PyKeyError, ffi, types::PyDict, AsPyPointer, FromPyObject, IntoPy, PyErr, PyObject, PyResult, Python,
+	ToPyObject,
+};
 
 /// An execution context for Python code.
 ///
@@ -49,15 +52,13 @@ impl Context {
 	/// This function panics if it fails to create the context.
 	/// See [`Context::new_checked`] for a version that returns a result.
 	pub fn new() -> Self {
-		let gil = Python::acquire_gil();
-		let py = gil.python();
-		match Self::new_with_gil(py) {
+		Python::with_gil(|py| match Self::new_with_gil(py) {
 			Ok(x) => x,
 			Err(error) => {
 				error.print(py);
 				panic!("failed to create python context");
 			}
-		}
+		})
 	}
 
 	/// Create a new context for running python code.
@@ -65,9 +66,7 @@ impl Context {
 	/// This function temporarily acquires the GIL.
 	/// If you already have the GIL, use [`Context::new_with_gil`] instead.
 	pub fn new_checked() -> PyResult<Self> {
-		let gil = Python::acquire_gil();
-		let py = gil.python();
-		Self::new_with_gil(py)
+		Python::with_gil(Self::new_with_gil)
 	}
 
 	/// Create a new context for running Python code.
@@ -89,30 +88,195 @@ impl Context {
 		})
 	}
 
+	/// Create an independent copy of this context.
+	///
+	/// The clone's globals start out as a fresh dictionary merged from `self`'s globals, so later
+	/// mutations on either context (e.g. via [`Context::set`]) do not affect the other. This is
+	/// useful to set up a template context once and spin off per-task variants cheaply.
+	///
+	/// You must acquire the GIL to call this function.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::Context;
+	/// let a = Context::new();
+	/// a.set("x", 1);
+	///
+	/// let b = a.with_gil(|py, a| a.clone_with_gil(py)).unwrap();
+	/// b.set("x", 2);
+	///
+	/// assert_eq!(a.get::<i32>("x"), 1);
+	/// assert_eq!(b.get::<i32>("x"), 2);
+	/// ```
+	pub fn clone_with_gil(&self, py: Python) -> PyResult<Self> {
+		let globals = PyDict::new(py);
+		if unsafe { ffi::PyDict_Merge(globals.as_ptr(), self.globals.as_ptr(), 1) != 0 } {
+			return Err(PyErr::fetch(py));
+		}
+
+		Ok(Self {
+			globals: globals.into_py(py),
+		})
+	}
+
+	/// Merge another context's globals into this one.
+	///
+	/// If `overwrite` is `true`, variables in `other` take precedence over variables already
+	/// present in `self` with the same name; otherwise `self`'s existing variables are kept.
+	///
+	/// This function temporarily acquires the GIL.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::Context;
+	/// let a = Context::new();
+	/// a.set("x", 1);
+	///
+	/// let b = Context::new();
+	/// b.set("x", 2);
+	///
+	/// a.merge_from(&b, false).unwrap();
+	/// assert_eq!(a.get::<i32>("x"), 1);
+	///
+	/// a.merge_from(&b, true).unwrap();
+	/// assert_eq!(a.get::<i32>("x"), 2);
+	/// ```
+	pub fn merge_from(&self, other: &Context, overwrite: bool) -> PyResult<()> {
+		Python::with_gil(|py| {
+			if unsafe { ffi::PyDict_Merge(self.globals.as_ptr(), other.globals.as_ptr(), overwrite as _) != 0 } {
+				return Err(PyErr::fetch(py));
+			}
+			Ok(())
+		})
+	}
+
+	/// Set many global variables in the context at once, under a single GIL acquisition.
+	///
+	/// This function temporarily acquires the GIL.
+	/// If you already have the GIL, call [`Context::set_with_gil`] for each variable instead.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::Context;
+	/// let c = Context::new();
+	///
+	/// c.set_many([("x", 1), ("y", 2)]);
+	/// assert_eq!(c.get::<i32>("x"), 1);
+	/// assert_eq!(c.get::<i32>("y"), 2);
+	/// ```
+	pub fn set_many<T: ToPyObject>(&self, values: impl IntoIterator<Item = (impl AsRef<str>, T)>) {
+		self.with_gil(|py, ctx| {
+			for (name, value) in values {
+				ctx.set_with_gil(py, name.as_ref(), value);
+			}
+		})
+	}
+
 	/// Get the globals as dictionary.
 	pub fn globals<'p>(&self, py: Python<'p>) -> &'p PyDict {
 		unsafe { py.from_borrowed_ptr(self.globals.as_ptr()) }
 	}
 
+	/// Run a closure with the GIL acquired, passing it both the `Python` token and this context.
+	///
+	/// Use this to batch several `_with_gil` calls (e.g. multiple [`Context::get_with_gil`] and
+	/// [`Context::set_with_gil`] calls) under a single GIL acquisition, instead of acquiring and
+	/// releasing the GIL for each one individually:
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::{Context, python};
+	/// let c = Context::new();
+	///
+	/// c.with_gil(|py, c| {
+	///   c.set_with_gil(py, "x", 13);
+	///   c.run_with_gil(py, python! { foo = x + 2 });
+	///   assert_eq!(c.get_with_gil::<i32>(py, "foo"), 15);
+	/// });
+	/// ```
+	pub fn with_gil<R>(&self, f: impl FnOnce(Python, &Self) -> R) -> R {
+		Python::with_gil(|py| f(py, self))
+	}
+
+	/// Run a Rust closure while releasing the GIL, so other Python threads can make progress.
+	///
+	/// You must already hold the GIL to call this function (see [`Context::with_gil`]) — this
+	/// deliberately does not acquire the GIL itself, since contending for it just to immediately
+	/// release it again would defeat the point of releasing it for a worker thread. No GIL-bound
+	/// reference (e.g. a `&PyAny` borrowed from [`Context::globals`]) may be captured in `f`, since
+	/// it would become dangling the moment the GIL is released; this is enforced by the `Send`
+	/// bound on `f`.
+	///
+	/// Any updates to `globals` based on the result of `f` must happen afterwards, inside the same
+	/// [`Context::with_gil`] block.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::{Context, python};
+	/// let c = Context::new();
+	///
+	/// c.with_gil(|py, c| {
+	///   let result = c.allow_threads(py, || expensive_computation());
+	///   c.set_with_gil(py, "result", result);
+	/// });
+	/// # fn expensive_computation() -> i32 { 42 }
+	/// ```
+	pub fn allow_threads<R: Send>(&self, py: Python, f: impl FnOnce() -> R + Send) -> R {
+		py.allow_threads(f)
+	}
+
 	/// Retrieve a global variable from the context.
 	///
 	/// This function temporarily acquires the GIL.
 	/// If you already have the GIL, use [`Context::get_with_gil`] instead.
+	///
+	/// This function panics if the variable does not exist or cannot be converted.
+	/// See [`Context::get_checked`] for a version that returns a result.
 	pub fn get<T: for<'p> FromPyObject<'p>>(&self, name: &str) -> T {
-		self.get_with_gil(Python::acquire_gil().python(), name)
+		Python::with_gil(|py| self.get_with_gil(py, name))
 	}
 
 	/// Retrieve a global variable from the context.
+	///
+	/// This function panics if the variable does not exist or cannot be converted.
+	/// See [`Context::get_with_gil_checked`] for a version that returns a result.
 	pub fn get_with_gil<'p, T: FromPyObject<'p>>(&self, py: Python<'p>, name: &str) -> T {
+		match self.get_with_gil_checked(py, name) {
+			Ok(value) => value,
+			Err(e) => {
+				e.print(py);
+				panic!("Unable to get `{}` as `{}`", name, std::any::type_name::<T>());
+			}
+		}
+	}
+
+	/// Retrieve a global variable from the context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// This function temporarily acquires the GIL.
+	/// If you already have the GIL, use [`Context::get_with_gil_checked`] instead.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::Context;
+	/// let c = Context::new();
+	///
+	/// assert!(c.get_checked::<i32>("does_not_exist").is_err());
+	/// ```
+	pub fn get_checked<T: for<'p> FromPyObject<'p>>(&self, name: &str) -> PyResult<T> {
+		Python::with_gil(|py| self.get_with_gil_checked(py, name))
+	}
+
+	/// Retrieve a global variable from the context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// Returns a `PyKeyError` if no variable named `name` exists, or the conversion error from
+	/// [`FromPyObject::extract`] if the value could not be converted to `T`.
+	pub fn get_with_gil_checked<'p, T: FromPyObject<'p>>(&self, py: Python<'p>, name: &str) -> PyResult<T> {
 		match self.globals(py).get_item(name) {
-			None => panic!("Python context does not contain a variable named `{}`", name),
-			Some(value) => match FromPyObject::extract(value) {
-				Ok(value) => value,
-				Err(e) => {
-					e.print(py);
-					panic!("Unable to convert `{}` to `{}`", name, std::any::type_name::<T>());
-				}
-			},
+			None => Err(PyErr::new::<PyKeyError, _>(format!(
+				"Python context does not contain a variable named `{}`",
+				name
+			))),
+			Some(value) => FromPyObject::extract(value),
 		}
 	}
 
@@ -120,21 +284,47 @@ impl Context {
 	///
 	/// This function temporarily acquires the GIL.
 	/// If you already have the GIL, use [`Context::set_with_gil`] instead.
+	///
+	/// This function panics if it fails to set the variable.
+	/// See [`Context::set_checked`] for a version that returns a result.
 	pub fn set<T: ToPyObject>(&self, name: &str, value: T) {
-		self.set_with_gil(Python::acquire_gil().python(), name, value)
+		Python::with_gil(|py| self.set_with_gil(py, name, value))
 	}
 
 	/// Set a global variable in the context.
+	///
+	/// This function panics if it fails to set the variable.
+	/// See [`Context::set_with_gil_checked`] for a version that returns a result.
 	pub fn set_with_gil<'p, T: ToPyObject>(&self, py: Python<'p>, name: &str, value: T) {
-		match self.globals(py).set_item(name, value) {
-			Ok(()) => (),
-			Err(e) => {
-				e.print(py);
-				panic!("Unable to set `{}` from a `{}`", name, std::any::type_name::<T>());
-			}
+		let type_name = std::any::type_name::<T>();
+		if let Err(e) = self.set_with_gil_checked(py, name, value) {
+			e.print(py);
+			panic!("Unable to set `{}` from a `{}`", name, type_name);
 		}
 	}
 
+	/// Set a global variable in the context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// This function temporarily acquires the GIL.
+	/// If you already have the GIL, use [`Context::set_with_gil_checked`] instead.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::Context;
+	/// let c = Context::new();
+	///
+	/// assert!(c.set_checked("x", 13).is_ok());
+	/// assert_eq!(c.get::<i32>("x"), 13);
+	/// ```
+	pub fn set_checked<T: ToPyObject>(&self, name: &str, value: T) -> PyResult<()> {
+		Python::with_gil(|py| self.set_with_gil_checked(py, name, value))
+	}
+
+	/// Set a global variable in the context, returning a `PyErr` instead of panicking on failure.
+	pub fn set_with_gil_checked<'p, T: ToPyObject>(&self, py: Python<'p>, name: &str, value: T) -> PyResult<()> {
+		self.globals(py).set_item(name, value)
+	}
+
 	/// Run Python code using this context.
 	///
 	/// This function should be called using the `python!{}` macro:
@@ -151,22 +341,126 @@ impl Context {
 	///
 	/// This function temporarily acquires the GIL.
 	/// If you already have the GIL, use [`Context::run_with_gil`] instead.
+	///
+	/// This function panics if the code fails to execute.
+	/// See [`Context::run_checked`] for a version that returns a result.
 	pub fn run<F: FnOnce(&PyDict)>(&self, code: PythonBlock<F>) {
-		self.run_with_gil(Python::acquire_gil().python(), code);
+		Python::with_gil(|py| self.run_with_gil(py, code));
 	}
 
 	/// Run Python code using this context.
 	///
 	/// This function should be called using the `python!{}` macro, just like
 	/// [`Context::run`].
+	///
+	/// This function panics if the code fails to execute.
+	/// See [`Context::run_with_gil_checked`] for a version that returns a result.
 	pub fn run_with_gil<'p, F: FnOnce(&PyDict)>(&self, py: Python<'p>, code: PythonBlock<F>) {
-		(code.set_variables)(self.globals(py));
-		match run_python_code(py, self, code.bytecode) {
-			Ok(_) => (),
+		match self.run_with_gil_checked(py, code) {
+			Ok(()) => (),
 			Err(e) => {
 				e.print(py);
 				panic!("python!{...} failed to execute");
 			}
 		}
 	}
+
+	/// Run Python code using this context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// This function should be called using the `python!{}` macro, just like [`Context::run`].
+	///
+	/// This function temporarily acquires the GIL.
+	/// If you already have the GIL, use [`Context::run_with_gil_checked`] instead.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::{Context, python};
+	/// let c = Context::new();
+	///
+	/// assert!(c.run_checked(python! { raise Exception("boom") }).is_err());
+	/// ```
+	pub fn run_checked<F: FnOnce(&PyDict)>(&self, code: PythonBlock<F>) -> PyResult<()> {
+		Python::with_gil(|py| self.run_with_gil_checked(py, code))
+	}
+
+	/// Run Python code using this context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// This function should be called using the `python!{}` macro, just like [`Context::run`].
+	pub fn run_with_gil_checked<'p, F: FnOnce(&PyDict)>(&self, py: Python<'p>, code: PythonBlock<F>) -> PyResult<()> {
+		(code.set_variables)(self.globals(py));
+		run_python_code(py, self, code.bytecode)?;
+		Ok(())
+	}
+
+	/// Evaluate a Python expression using this context and extract the result.
+	///
+	/// This function should be called using the `python!{}` macro, just like [`Context::run`],
+	/// except that the block must be a single expression.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::{Context, python};
+	/// let c = Context::new();
+	///
+	/// let n: i64 = c.eval(python! { 1 + 2 });
+	/// assert_eq!(n, 3);
+	/// ```
+	///
+	/// This function temporarily acquires the GIL.
+	/// If you already have the GIL, use [`Context::eval_with_gil`] instead.
+	///
+	/// This function panics if the code fails to evaluate or the result cannot be converted.
+	/// See [`Context::eval_checked`] for a version that returns a result.
+	pub fn eval<T: for<'p> FromPyObject<'p>, F: FnOnce(&PyDict)>(&self, code: PythonBlock<F>) -> T {
+		Python::with_gil(|py| self.eval_with_gil(py, code))
+	}
+
+	/// Evaluate a Python expression using this context and extract the result.
+	///
+	/// This function panics if the code fails to evaluate or the result cannot be converted.
+	/// See [`Context::eval_with_gil_checked`] for a version that returns a result.
+	pub fn eval_with_gil<T: for<'p> FromPyObject<'p>, F: FnOnce(&PyDict)>(&self, py: Python, code: PythonBlock<F>) -> T {
+		match self.eval_with_gil_checked(py, code) {
+			Ok(value) => value,
+			Err(e) => {
+				e.print(py);
+				panic!("python!{...} failed to evaluate");
+			}
+		}
+	}
+
+	/// Evaluate a Python expression using this context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// This function temporarily acquires the GIL.
+	/// If you already have the GIL, use [`Context::eval_with_gil_checked`] instead.
+	///
+	/// ```
+	/// # #![feature(proc_macro_hygiene)]
+	/// # use inline_python::{Context, python};
+	/// let c = Context::new();
+	///
+	/// assert!(c.eval_checked::<i64, _>(python! { 1 / 0 }).is_err());
+	/// ```
+	pub fn eval_checked<T: for<'p> FromPyObject<'p>, F: FnOnce(&PyDict)>(&self, code: PythonBlock<F>) -> PyResult<T> {
+		Python::with_gil(|py| self.eval_with_gil_checked(py, code))
+	}
+
+	/// Evaluate a Python expression using this context, returning a `PyErr` instead of panicking on failure.
+	///
+	/// The block passed to the `python!{}` macro must compile as a single expression (`Py_eval_input`)
+	/// rather than as statements (`Py_file_input`, as used by [`Context::run`]).
+	///
+	/// `T` is bound for any lifetime rather than the caller's `py` lifetime, because the evaluated
+	/// value is freshly owned by this function (unlike [`Context::get_with_gil_checked`], which
+	/// extracts from a reference borrowed from the long-lived `globals` dict) and so can only be
+	/// extracted from for as long as this function body keeps it alive.
+	pub fn eval_with_gil_checked<T: for<'p> FromPyObject<'p>, F: FnOnce(&PyDict)>(
+		&self,
+		py: Python,
+		code: PythonBlock<F>,
+	) -> PyResult<T> {
+		(code.set_variables)(self.globals(py));
+		let value = eval_python_code(py, self, code.bytecode)?;
+		value.extract(py)
+	}
 }